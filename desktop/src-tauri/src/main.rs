@@ -2,18 +2,79 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use portable_pty::{native_pty_system, CommandBuilder, PtySize};
+use std::collections::HashMap;
 use std::io::{Read, Write};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
-use tauri::{AppHandle, Emitter, State};
+use tauri::{AppHandle, Emitter, Manager, RunEvent, State};
+use uuid::Uuid;
 
-struct PtyState {
-    writer: Arc<Mutex<Option<Box<dyn Write + Send>>>>,
-    master: Arc<Mutex<Option<Box<dyn portable_pty::MasterPty + Send>>>>,
+const APP_NAME: &str = "doctown";
+
+/// Persisted settings for what `spawn_pty` runs. Defaults match the
+/// historical hardcoded behavior: `uv run docpack deck` with the cwd
+/// auto-detected by walking up from the executable to find `pyproject.toml`.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+struct AppConfig {
+    command: String,
+    args: Vec<String>,
+    cwd: Option<String>,
+    env: HashMap<String, String>,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            command: "uv".to_string(),
+            args: vec!["run".to_string(), "docpack".to_string(), "deck".to_string()],
+            cwd: None,
+            env: HashMap::new(),
+        }
+    }
+}
+
+struct PtySession {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    master: Arc<Mutex<Box<dyn portable_pty::MasterPty + Send>>>,
+    child: Arc<Mutex<Box<dyn portable_pty::Child + Send>>>,
+    manually_killed: Arc<AtomicBool>,
+}
+
+impl PtySession {
+    fn kill(&self) {
+        self.manually_killed.store(true, Ordering::SeqCst);
+        let _ = self.child.lock().unwrap().kill();
+    }
+}
+
+#[derive(Default)]
+struct PtyState(RwLock<HashMap<String, PtySession>>);
+
+#[derive(serde::Serialize, Clone)]
+struct PtyOutputEvent {
+    id: String,
+    data: String,
+}
+
+#[derive(serde::Serialize, Clone)]
+struct PtyExitEvent {
+    id: String,
+    code: u32,
+    manually_killed: bool,
 }
 
 #[tauri::command]
-fn spawn_pty(cols: u16, rows: u16, app: AppHandle, state: State<PtyState>) -> Result<(), String> {
+fn spawn_pty(
+    cols: u16,
+    rows: u16,
+    env: Option<HashMap<String, String>>,
+    app: AppHandle,
+    state: State<PtyState>,
+    config: State<Mutex<AppConfig>>,
+) -> Result<String, String> {
+    let config = config.lock().unwrap().clone();
+
     let pty_system = native_pty_system();
 
     let pair = pty_system
@@ -25,111 +86,281 @@ fn spawn_pty(cols: u16, rows: u16, app: AppHandle, state: State<PtyState>) -> Re
         })
         .map_err(|e| e.to_string())?;
 
-    // Build the command to run docpack deck
-    // Use uv run to work with the local project without needing global install
-    let mut cmd = CommandBuilder::new("uv");
-    cmd.args(["run", "docpack", "deck"]);
-
-    // Set working directory to the project root (parent of desktop/)
-    let exe_dir = std::env::current_exe()
-        .ok()
-        .and_then(|p| p.parent().map(|p| p.to_path_buf()));
-
-    // In dev mode, exe is in desktop/src-tauri/target/debug
-    // We need to go up to the project root
-    if let Some(dir) = exe_dir {
-        // Try to find pyproject.toml by walking up
-        let mut search_dir = dir.as_path();
-        for _ in 0..10 {
-            let pyproject = search_dir.join("pyproject.toml");
-            if pyproject.exists() {
-                cmd.cwd(search_dir);
-                break;
-            }
-            if let Some(parent) = search_dir.parent() {
-                search_dir = parent;
+    // Build the configured command (defaults to `uv run docpack deck`)
+    let mut cmd = CommandBuilder::new(&config.command);
+    cmd.args(&config.args);
+    for (key, value) in &config.env {
+        cmd.env(key, value);
+    }
+    for (key, value) in env.unwrap_or_default() {
+        cmd.env(key, value);
+    }
+
+    // Context the spawned process can rely on without having to introspect its own host.
+    cmd.env("DOCTOWN_DESKTOP", "1");
+    cmd.env("DOCTOWN_OS", std::env::consts::OS);
+    cmd.env("DOCTOWN_ARCH", std::env::consts::ARCH);
+    cmd.env("DOCTOWN_FAMILY", std::env::consts::FAMILY);
+    cmd.env(
+        "DOCTOWN_TARGET_TRIPLE",
+        format!(
+            "{}-{}-{}",
+            std::env::consts::ARCH,
+            if cfg!(target_vendor = "apple") {
+                "apple"
+            } else if cfg!(windows) {
+                "pc"
+            } else {
+                "unknown"
+            },
+            // The real triple's OS component is "darwin" on Apple targets, not
+            // `std::env::consts::OS`'s "macos" (e.g. x86_64-apple-darwin).
+            if cfg!(target_vendor = "apple") {
+                "darwin"
             } else {
-                break;
+                std::env::consts::OS
+            },
+        ),
+    );
+
+    if let Some(cwd) = &config.cwd {
+        cmd.cwd(cwd);
+    } else {
+        // Set working directory to the project root (parent of desktop/)
+        let exe_dir = std::env::current_exe()
+            .ok()
+            .and_then(|p| p.parent().map(|p| p.to_path_buf()));
+
+        // In dev mode, exe is in desktop/src-tauri/target/debug
+        // We need to go up to the project root
+        if let Some(dir) = exe_dir {
+            // Try to find pyproject.toml by walking up
+            let mut search_dir = dir.as_path();
+            for _ in 0..10 {
+                let pyproject = search_dir.join("pyproject.toml");
+                if pyproject.exists() {
+                    cmd.cwd(search_dir);
+                    break;
+                }
+                if let Some(parent) = search_dir.parent() {
+                    search_dir = parent;
+                } else {
+                    break;
+                }
             }
         }
     }
 
     // Spawn the command in the PTY
-    let mut child = pair.slave.spawn_command(cmd).map_err(|e| e.to_string())?;
+    let child = pair.slave.spawn_command(cmd).map_err(|e| e.to_string())?;
+    let child: Arc<Mutex<Box<dyn portable_pty::Child + Send>>> = Arc::new(Mutex::new(child));
 
     // Get reader from master before moving it
     let mut reader = pair.master.try_clone_reader().map_err(|e| e.to_string())?;
 
     // Store the writer for sending input
-    let writer = pair.master.take_writer().map_err(|e| e.to_string())?;
-    *state.writer.lock().unwrap() = Some(writer);
+    let writer = Arc::new(Mutex::new(pair.master.take_writer().map_err(|e| e.to_string())?));
 
     // Store the master for resize operations
-    *state.master.lock().unwrap() = Some(pair.master);
+    let master = Arc::new(Mutex::new(pair.master));
+
+    let manually_killed = Arc::new(AtomicBool::new(false));
+
+    let id = Uuid::new_v4().to_string();
+    state.0.write().unwrap().insert(
+        id.clone(),
+        PtySession {
+            writer,
+            master,
+            child: child.clone(),
+            manually_killed: manually_killed.clone(),
+        },
+    );
 
     // Spawn a thread to read PTY output and send to frontend
     let app_handle = app.clone();
+    let reader_id = id.clone();
 
     thread::spawn(move || {
         let mut buf = [0u8; 4096];
+        // Bytes from the previous read that didn't form a complete UTF-8
+        // sequence yet (at most 3, the longest a multibyte char can trail by).
+        let mut carry: Vec<u8> = Vec::new();
         loop {
             match reader.read(&mut buf) {
                 Ok(0) => break, // EOF
                 Ok(n) => {
-                    let data = String::from_utf8_lossy(&buf[..n]).to_string();
-                    let _ = app_handle.emit("pty-output", data);
+                    carry.extend_from_slice(&buf[..n]);
+                    let valid_up_to = match std::str::from_utf8(&carry) {
+                        Ok(s) => s.len(),
+                        Err(e) => e.valid_up_to(),
+                    };
+                    let tail = carry.split_off(valid_up_to);
+                    let mut data =
+                        String::from_utf8(std::mem::replace(&mut carry, tail))
+                            .expect("validated above");
+
+                    // `carry` now holds whatever trailed the valid prefix. Tell a
+                    // genuinely invalid byte sequence (error_len() == Some(n)) apart
+                    // from one that's merely incomplete so far (None) — otherwise a
+                    // single invalid byte (e.g. stray 0xFF) pins valid_up_to forever
+                    // and the session appears to freeze.
+                    // Repeat until only an incomplete trailing sequence (or nothing) is left.
+                    loop {
+                        let (valid, invalid_len) = match std::str::from_utf8(&carry) {
+                            Ok(_) => (carry.len(), 0),
+                            Err(e) => (e.valid_up_to(), e.error_len().unwrap_or(0)),
+                        };
+                        if valid + invalid_len == 0 {
+                            break;
+                        }
+                        data.push_str(&String::from_utf8_lossy(&carry[..valid + invalid_len]));
+                        carry.drain(..valid + invalid_len);
+                    }
+                    // Cap the retained tail at 3 bytes (the longest a valid
+                    // continuation can be); anything longer can't be an
+                    // in-progress sequence, so flush it lossily instead of
+                    // growing `carry` unboundedly.
+                    if carry.len() > 3 {
+                        data.push_str(&String::from_utf8_lossy(&carry));
+                        carry.clear();
+                    }
+
+                    if !data.is_empty() {
+                        let _ = app_handle.emit(
+                            "pty-output",
+                            PtyOutputEvent {
+                                id: reader_id.clone(),
+                                data,
+                            },
+                        );
+                    }
                 }
                 Err(_) => break,
             }
         }
+        // Stream ended with a dangling partial sequence; flush it lossily rather
+        // than silently dropping the tail.
+        if !carry.is_empty() {
+            let data = String::from_utf8_lossy(&carry).to_string();
+            let _ = app_handle.emit(
+                "pty-output",
+                PtyOutputEvent {
+                    id: reader_id.clone(),
+                    data,
+                },
+            );
+        }
     });
 
-    // Spawn a thread to wait for child exit
+    // Spawn a thread to wait for child exit. We poll with try_wait() instead of a
+    // blocking wait() so the mutex is only held briefly each tick, leaving
+    // kill_pty free to grab it and signal the child at any time.
     let app_handle = app.clone();
+    let exit_id = id.clone();
     thread::spawn(move || {
-        if let Ok(status) = child.wait() {
-            let code = status.exit_code();
-            let _ = app_handle.emit("pty-exit", code);
-        }
+        let code = loop {
+            let status = child.lock().unwrap().try_wait();
+            match status {
+                Ok(Some(status)) => break status.exit_code(),
+                Ok(None) => thread::sleep(std::time::Duration::from_millis(25)),
+                Err(_) => break 1,
+            }
+        };
+        let _ = app_handle.emit(
+            "pty-exit",
+            PtyExitEvent {
+                id: exit_id.clone(),
+                code,
+                manually_killed: manually_killed.load(Ordering::SeqCst),
+            },
+        );
+        // The session is done for good at this point (natural exit or kill_pty);
+        // drop it so dead ids aren't still addressable via write_pty/resize_pty
+        // and the map doesn't grow unbounded over the app's lifetime.
+        app_handle.state::<PtyState>().0.write().unwrap().remove(&exit_id);
     });
 
+    Ok(id)
+}
+
+#[tauri::command]
+fn get_config(config: State<Mutex<AppConfig>>) -> AppConfig {
+    config.lock().unwrap().clone()
+}
+
+#[tauri::command]
+fn set_config(new_config: AppConfig, config: State<Mutex<AppConfig>>) -> Result<(), String> {
+    confy::store(APP_NAME, None, &new_config).map_err(|e| e.to_string())?;
+    *config.lock().unwrap() = new_config;
     Ok(())
 }
 
 #[tauri::command]
-fn write_pty(data: String, state: State<PtyState>) -> Result<(), String> {
-    if let Some(ref mut writer) = *state.writer.lock().unwrap() {
-        writer
-            .write_all(data.as_bytes())
-            .map_err(|e| e.to_string())?;
-        writer.flush().map_err(|e| e.to_string())?;
-    }
+fn kill_pty(id: String, state: State<PtyState>) -> Result<(), String> {
+    let sessions = state.0.read().unwrap();
+    let session = sessions
+        .get(&id)
+        .ok_or_else(|| format!("no such pty session: {id}"))?;
+    session.kill();
     Ok(())
 }
 
 #[tauri::command]
-fn resize_pty(cols: u16, rows: u16, state: State<PtyState>) -> Result<(), String> {
-    if let Some(ref master) = *state.master.lock().unwrap() {
-        master
-            .resize(PtySize {
-                rows,
-                cols,
-                pixel_width: 0,
-                pixel_height: 0,
-            })
-            .map_err(|e| e.to_string())?;
-    }
+fn write_pty(id: String, data: String, state: State<PtyState>) -> Result<(), String> {
+    let sessions = state.0.read().unwrap();
+    let session = sessions
+        .get(&id)
+        .ok_or_else(|| format!("no such pty session: {id}"))?;
+    let mut writer = session.writer.lock().unwrap();
+    writer.write_all(data.as_bytes()).map_err(|e| e.to_string())?;
+    writer.flush().map_err(|e| e.to_string())?;
     Ok(())
 }
 
+#[tauri::command]
+fn resize_pty(id: String, cols: u16, rows: u16, state: State<PtyState>) -> Result<(), String> {
+    let sessions = state.0.read().unwrap();
+    let session = sessions
+        .get(&id)
+        .ok_or_else(|| format!("no such pty session: {id}"))?;
+    session
+        .master
+        .lock()
+        .unwrap()
+        .resize(PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        })
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn kill_all_sessions(state: &PtyState) {
+    for session in state.0.read().unwrap().values() {
+        session.kill();
+    }
+}
+
 fn main() {
-    tauri::Builder::default()
+    let config: AppConfig = confy::load(APP_NAME, None).unwrap_or_default();
+
+    let app = tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
-        .manage(PtyState {
-            writer: Arc::new(Mutex::new(None)),
-            master: Arc::new(Mutex::new(None)),
-        })
-        .invoke_handler(tauri::generate_handler![spawn_pty, write_pty, resize_pty])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .manage(PtyState::default())
+        .manage(Mutex::new(config))
+        .invoke_handler(tauri::generate_handler![
+            spawn_pty, write_pty, resize_pty, kill_pty, get_config, set_config
+        ])
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application");
+
+    app.run(|app_handle, event| {
+        // Make sure no spawned uv/docpack process outlives the window it was opened from.
+        if let RunEvent::ExitRequested { .. } = event {
+            kill_all_sessions(&app_handle.state::<PtyState>());
+        }
+    });
 }